@@ -0,0 +1,362 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::Result;
+
+use crate::archive;
+use crate::errors::FfiError;
+use crate::gps_processor::{self, GpsProcessor, ProcessResult};
+use crate::gps_wal::GpsWal;
+use crate::import_data::{self, ImportResult, ImportType};
+use crate::journey_bitmap::JourneyBitmap;
+use crate::journey_data::JourneyData;
+use crate::journey_header::JourneyHeader;
+use crate::map_renderer::{MapRenderer, RenderResult};
+use crate::merged_journey_builder;
+use crate::storage::{self, Storage};
+
+/// Everything an FFI call might need from the single owning thread. Every
+/// variant carries its own reply channel (a one-shot `std::sync::mpsc`
+/// pair) except the fire-and-forget ones, so the caller still gets a
+/// synchronous-looking result without anyone outside this module ever
+/// touching `Storage`/`MapRenderer`/`GpsProcessor` directly.
+pub enum Command {
+    OnLocationUpdate {
+        raw_data_list: Vec<gps_processor::RawData>,
+        recevied_timestamp_ms: i64,
+    },
+    RenderOverlay {
+        zoom: i32,
+        left: f64,
+        top: f64,
+        right: f64,
+        bottom: f64,
+        reply: Sender<std::result::Result<Option<RenderResult>, FfiError>>,
+    },
+    ResetMainMapRenderer {
+        reply: Sender<std::result::Result<(), FfiError>>,
+    },
+    JourneyBitmapFor {
+        journey_id: String,
+        reply: Sender<Result<JourneyBitmap>>,
+    },
+    ListAllRawData {
+        reply: Sender<Vec<storage::RawDataFile>>,
+    },
+    GetRawDataMode {
+        reply: Sender<bool>,
+    },
+    ToggleRawDataMode {
+        enable: bool,
+    },
+    DeleteRawDataFile {
+        filename: String,
+        reply: Sender<Result<()>>,
+    },
+    DeleteJourney {
+        journey_id: String,
+        reply: Sender<Result<()>>,
+    },
+    FinalizeJourney {
+        reply: Sender<Result<bool>>,
+    },
+    TryAutoFinalizeJourney {
+        reply: Sender<Result<bool>>,
+    },
+    ListAllJourneys {
+        reply: Sender<Result<Vec<JourneyHeader>>>,
+    },
+    /// The read side of `generate_full_archive`: everything the zip writer
+    /// needs, already pulled out of the db into memory. Deliberately *not*
+    /// a `GenerateFullArchive` command that does the zip writing itself —
+    /// compressing thousands of journeys can take a while, and running
+    /// that on this thread would block every other FFI call for the
+    /// duration. The caller does the actual compression off this thread
+    /// once it has the snapshot; see `api::generate_full_archive_cancellable`.
+    SnapshotJourneysForArchive {
+        reply: Sender<Result<Vec<(String, JourneyData)>>>,
+    },
+    /// The read side of `export_journey`, for the same reason: the lookup
+    /// is quick, the GPX/KML encoding afterwards is not, so only the
+    /// lookup happens here.
+    GetJourneyForExport {
+        journey_id: String,
+        reply: Sender<Result<JourneyData>>,
+    },
+    /// The write side of `recover_from_archive`: the zip is read and every
+    /// entry decompressed off this thread first (see
+    /// `api::recover_from_archive_cancellable`), and only the already-decoded
+    /// journeys are committed here, one db transaction for the whole batch.
+    CommitRecoveredJourneys {
+        journeys: Vec<(String, JourneyData)>,
+        cancelled: Arc<AtomicBool>,
+        reply: Sender<Result<()>>,
+    },
+    ImportJourney {
+        source_filepath: String,
+        import_type: ImportType,
+        merge_tracks: bool,
+        reply: Sender<Result<ImportResult>>,
+    },
+}
+
+/// Handle to the single thread that owns `Storage`, `MapRenderer` and
+/// `GpsProcessor`. Replaces the old `storage`/`map_renderer`/`gps_processor`
+/// mutexes: instead of locking several of them in a fixed order (and
+/// risking a deadlock the moment a new call site picks a different order),
+/// every FFI function just sends a `Command` here and, if it needs a
+/// result, blocks on the one-shot reply.
+#[derive(Clone)]
+pub struct Actor {
+    sender: Sender<Command>,
+}
+
+impl Actor {
+    pub fn spawn(storage: Storage, gps_wal: GpsWal) -> Self {
+        let (sender, receiver) = mpsc::channel::<Command>();
+        thread::spawn(move || {
+            let mut state = ActorState {
+                storage,
+                map_renderer: None,
+                gps_processor: GpsProcessor::new(),
+                gps_wal,
+            };
+            for command in receiver {
+                // A panic in a single command handler must not take the
+                // whole actor thread down: that would strand every future
+                // `call()` on a disconnected channel. The in-flight reply
+                // sender (if any) is dropped along with the unwound frame,
+                // so the caller's `recv()` fails instead of hanging, and
+                // `call()` turns that into a recoverable `FfiError`.
+                if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| state.handle(command)))
+                    .is_err()
+                {
+                    error!("actor command handler panicked; continuing to process further commands");
+                }
+            }
+        });
+        Actor { sender }
+    }
+
+    /// Queues `command` for the actor thread and returns without waiting.
+    /// A caller that needs an answer embeds a `reply` sender in the command
+    /// and blocks on its receiving end itself (see `api::call`).
+    pub fn send(&self, command: Command) {
+        // The receiving end only ever goes away if the actor thread itself
+        // panicked; in that case there is nothing a dropped command could
+        // do for us, so we just let the (absent) reply recv surface it.
+        let _ = self.sender.send(command);
+    }
+}
+
+struct ActorState {
+    storage: Storage,
+    map_renderer: Option<MapRenderer>,
+    gps_processor: GpsProcessor,
+    gps_wal: GpsWal,
+}
+
+impl ActorState {
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::OnLocationUpdate {
+                raw_data_list,
+                recevied_timestamp_ms,
+            } => self.on_location_update(raw_data_list, recevied_timestamp_ms),
+            Command::RenderOverlay {
+                zoom,
+                left,
+                top,
+                right,
+                bottom,
+                reply,
+            } => {
+                let _ = reply.send(self.render_overlay(zoom, left, top, right, bottom));
+            }
+            Command::ResetMainMapRenderer { reply } => {
+                if let Some(map_renderer) = &mut self.map_renderer {
+                    map_renderer.reset();
+                }
+                let _ = reply.send(std::result::Result::Ok(()));
+            }
+            Command::JourneyBitmapFor { journey_id, reply } => {
+                let _ = reply.send(self.journey_bitmap_for(&journey_id));
+            }
+            Command::ListAllRawData { reply } => {
+                let _ = reply.send(self.storage.list_all_raw_data());
+            }
+            Command::GetRawDataMode { reply } => {
+                let _ = reply.send(self.storage.get_raw_data_mode());
+            }
+            Command::ToggleRawDataMode { enable } => {
+                self.storage.toggle_raw_data_mode(enable);
+            }
+            Command::DeleteRawDataFile { filename, reply } => {
+                let _ = reply.send(self.storage.delete_raw_data_file(filename));
+            }
+            Command::DeleteJourney { journey_id, reply } => {
+                let _ = reply.send(
+                    self.storage
+                        .with_db_txn(|txn| txn.delete_journey(&journey_id)),
+                );
+            }
+            Command::FinalizeJourney { reply } => {
+                let _ = reply.send(self.storage.with_db_txn(|txn| txn.finalize_ongoing_journey()));
+            }
+            Command::TryAutoFinalizeJourney { reply } => {
+                let _ = reply.send(
+                    self.storage
+                        .with_db_txn(|txn| txn.try_auto_finalize_journy()),
+                );
+            }
+            Command::ListAllJourneys { reply } => {
+                let _ = reply.send(self.storage.with_db_txn(|txn| txn.list_all_journeys()));
+            }
+            Command::SnapshotJourneysForArchive { reply } => {
+                let _ = reply.send(
+                    self.storage
+                        .with_db_txn(archive::collect_journeys_for_archive),
+                );
+            }
+            Command::GetJourneyForExport { journey_id, reply } => {
+                let _ = reply.send(self.storage.with_db_txn(|txn| txn.get_journey(&journey_id)));
+            }
+            Command::CommitRecoveredJourneys {
+                journeys,
+                cancelled,
+                reply,
+            } => {
+                let _ = reply.send(self.storage.with_db_txn(|txn| {
+                    for (id, data) in journeys {
+                        if cancelled.load(Ordering::Acquire) {
+                            bail!("cancelled");
+                        }
+                        txn.insert_journey(&id, data)?;
+                    }
+                    Ok(())
+                }));
+            }
+            Command::ImportJourney {
+                source_filepath,
+                import_type,
+                merge_tracks,
+                reply,
+            } => {
+                let _ = reply.send(import_data::import_journey(
+                    &self.storage,
+                    &source_filepath,
+                    import_type,
+                    merge_tracks,
+                ));
+            }
+        }
+    }
+
+    fn on_location_update(&mut self, mut raw_data_list: Vec<gps_processor::RawData>, recevied_timestamp_ms: i64) {
+        // NOTE: On Android, we might recevied a batch of location updates that are out of order.
+        // Not very sure why yet.
+        raw_data_list.sort_by(|a, b| a.timestamp_ms.cmp(&b.timestamp_ms));
+
+        // durably record the batch before touching the db, so a crash
+        // between here and the commit below just means we replay it on
+        // next `init`.
+        if let Err(err) = self.gps_wal.append(&raw_data_list, recevied_timestamp_ms) {
+            warn!("failed to append gps wal, continuing without it: {}", err);
+        }
+
+        for raw_data in raw_data_list {
+            let line_to_add = process_one(&mut self.gps_processor, &self.storage, &raw_data, recevied_timestamp_ms);
+            if let (Some(map_renderer), Some((start, end))) = (&mut self.map_renderer, line_to_add) {
+                map_renderer.update(|journey_bitmap| {
+                    journey_bitmap.add_line(start.longitude, start.latitude, end.longitude, end.latitude);
+                });
+            }
+        }
+
+        if let Err(err) = self.gps_wal.advance() {
+            warn!("failed to advance gps wal: {}", err);
+        }
+    }
+
+    fn render_overlay(
+        &mut self,
+        zoom: i32,
+        left: f64,
+        top: f64,
+        right: f64,
+        bottom: f64,
+    ) -> std::result::Result<Option<RenderResult>, FfiError> {
+        if self.storage.main_map_renderer_need_to_reload() {
+            self.map_renderer = None;
+        }
+
+        if self.map_renderer.is_none() {
+            let journey_bitmap = self
+                .storage
+                .get_latest_bitmap_for_main_map_renderer()
+                .map_err(|err| FfiError::CorruptBitmap(err.to_string()))?;
+            self.map_renderer = Some(MapRenderer::new(journey_bitmap));
+        }
+
+        std::result::Result::Ok(
+            self.map_renderer
+                .as_mut()
+                .expect("just populated above")
+                .maybe_render_map_overlay(zoom, left, top, right, bottom),
+        )
+    }
+
+    fn journey_bitmap_for(&mut self, journey_id: &str) -> Result<JourneyBitmap> {
+        let journey_data = self.storage.with_db_txn(|txn| txn.get_journey(journey_id))?;
+        Ok(match journey_data {
+            JourneyData::Bitmap(bitmap) => bitmap,
+            JourneyData::Vector(vector) => {
+                let mut bitmap = JourneyBitmap::new();
+                merged_journey_builder::add_journey_vector_to_journey_bitmap(&mut bitmap, &vector);
+                bitmap
+            }
+        })
+    }
+
+}
+
+/// Preprocesses and persists one point, returning the line segment (if
+/// any) that should be drawn onto a live map renderer.
+fn process_one(
+    gps_processor: &mut GpsProcessor,
+    storage: &Storage,
+    raw_data: &gps_processor::RawData,
+    recevied_timestamp_ms: i64,
+) -> Option<(gps_processor::RawData, gps_processor::RawData)> {
+    // TODO: more batching updates
+    let last_data = gps_processor.last_data();
+    let process_result = gps_processor.preprocess(raw_data);
+    let line_to_add = match process_result {
+        ProcessResult::Ignore => None,
+        ProcessResult::NewSegment => Some((raw_data.clone(), raw_data.clone())),
+        ProcessResult::Append => {
+            let start = last_data.unwrap_or_else(|| raw_data.clone());
+            Some((start, raw_data.clone()))
+        }
+    };
+    storage.record_gps_data(raw_data, process_result, recevied_timestamp_ms);
+    line_to_add
+}
+
+/// Replays a single WAL-recovered batch through the exact same
+/// preprocessing/persistence path as a live update, minus the map
+/// renderer (there is no live map session to draw into during replay) and
+/// minus the WAL bookkeeping (the caller advances the WAL itself once the
+/// batch is done).
+pub fn replay_raw_data_batch(
+    storage: &Storage,
+    gps_processor: &mut GpsProcessor,
+    raw_data_list: Vec<gps_processor::RawData>,
+    recevied_timestamp_ms: i64,
+) {
+    for raw_data in raw_data_list {
+        process_one(gps_processor, storage, &raw_data, recevied_timestamp_ms);
+    }
+}
@@ -1,27 +1,41 @@
-use std::cmp::max;
 use std::fs::File;
 use std::path::Path;
-use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, OnceLock};
+use std::thread;
 
 use anyhow::{Ok, Result};
 use flutter_rust_bridge::frb;
+use flutter_rust_bridge::StreamSink;
 use simplelog::{Config, LevelFilter, WriteLogger};
 
-use crate::gps_processor::{GpsProcessor, ProcessResult};
-use crate::journey_bitmap::JourneyBitmap;
+use crate::actor::{Actor, Command};
+use crate::archive;
+use crate::errors::FfiError;
+use crate::export_data;
+use crate::gps_wal::GpsWal;
+use crate::import_data::{ImportResult, ImportType};
+use crate::job_manager::{JobId, JobManager, JobProgress};
 use crate::journey_data::JourneyData;
 use crate::journey_header::JourneyHeader;
 use crate::map_renderer::{MapRenderer, RenderResult};
 use crate::storage::Storage;
-use crate::{archive, export_data, gps_processor, merged_journey_builder, storage};
-
-// TODO: we have way too many locking here and now it is hard to track.
-//  e.g. we could mess up with the order and cause a deadlock
+use crate::{gps_processor, storage};
+
+// A single actor thread owns `Storage`, `MapRenderer` and `GpsProcessor`
+// and drives all access to them through `Command`s sent over an `mpsc`
+// channel (see `crate::actor`). This removes the old `storage`/
+// `map_renderer`/`gps_processor` mutexes by construction: there is nothing
+// left to lock in the wrong order, because nothing outside the actor
+// thread ever touches that state directly.
 #[frb(ignore)]
 pub struct MainState {
-    pub storage: Storage,
-    pub map_renderer: Mutex<Option<MapRenderer>>,
-    pub gps_processor: Mutex<GpsProcessor>,
+    pub actor: Actor,
+    pub job_manager: Arc<JobManager>,
+    // defaults to available cores; throttled down via `set_archive_parallelism`
+    // on mobile under thermal/battery pressure.
+    pub archive_parallelism: AtomicUsize,
 }
 
 static MAIN_STATE: OnceLock<MainState> = OnceLock::new();
@@ -31,6 +45,32 @@ pub fn get() -> &'static MainState {
     MAIN_STATE.get().expect("main state is not initialized")
 }
 
+/// Sends a command built by `make` (which is handed the one-shot reply
+/// sender to embed) to the actor and blocks for its answer. If the reply
+/// sender is dropped without answering (the handler for this command
+/// panicked, or the actor thread is gone), this comes back as an
+/// `FfiError` instead of panicking the calling thread too.
+fn call<T: Send + 'static>(
+    make: impl FnOnce(Sender<T>) -> Command,
+) -> std::result::Result<T, FfiError> {
+    let (reply, rx) = std::sync::mpsc::channel();
+    get().actor.send(make(reply));
+    rx.recv().map_err(|_| {
+        FfiError::ActorUnavailable("the actor thread did not answer this call".to_string())
+    })
+}
+
+/// Runs `f` inside `catch_unwind`, matching the protection
+/// `render_map_overlay`/`reset_map_renderer` already have: a panic
+/// anywhere in an FFI-exposed entry point's body on the calling thread
+/// comes back as a plain error instead of taking that thread down with
+/// it. (A panic on the actor thread never reaches here as a panic: `call()`
+/// already reports it as an `FfiError`.)
+fn guarded<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(anyhow!("a native FFI call panicked")))
+}
+
 #[frb(sync)]
 pub fn short_commit_hash() -> String {
     env!("SHORT_COMMIT_HASH").to_string()
@@ -50,13 +90,18 @@ pub fn init(temp_dir: String, doc_dir: String, support_dir: String, cache_dir: S
         )
         .expect("Failed to initialize logging");
 
-        let storage = Storage::init(temp_dir, doc_dir, support_dir, cache_dir);
+        let storage = Storage::init(temp_dir, doc_dir, support_dir.clone(), cache_dir);
         info!("initialized");
 
+        let mut gps_wal = GpsWal::open(&support_dir).expect("failed to open gps wal");
+        replay_gps_wal(&storage, &mut gps_wal);
+
         MainState {
-            storage,
-            map_renderer: Mutex::new(None),
-            gps_processor: Mutex::new(GpsProcessor::new()),
+            actor: Actor::spawn(storage, gps_wal),
+            job_manager: Arc::new(JobManager::new()),
+            archive_parallelism: AtomicUsize::new(
+                thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            ),
         }
     });
     if already_initialized {
@@ -64,6 +109,35 @@ pub fn init(temp_dir: String, doc_dir: String, support_dir: String, cache_dir: S
     }
 }
 
+/// Replays any GPS batches that were written to the WAL but never
+/// confirmed committed, e.g. because the process was killed mid-batch.
+/// Runs before the actor thread is spawned, so there is no contention with
+/// `on_location_update` yet.
+fn replay_gps_wal(storage: &Storage, gps_wal: &mut GpsWal) {
+    let pending = gps_wal.pending_records().unwrap_or_else(|err| {
+        warn!("failed to read gps wal, dropping it: {}", err);
+        Vec::new()
+    });
+    if !pending.is_empty() {
+        warn!(
+            "replaying {} uncommitted gps batch(es) from wal",
+            pending.len()
+        );
+    }
+    let mut gps_processor = gps_processor::GpsProcessor::new();
+    for record in pending {
+        crate::actor::replay_raw_data_batch(
+            storage,
+            &mut gps_processor,
+            record.raw_data_list,
+            record.received_timestamp_ms,
+        );
+        if let Err(err) = gps_wal.advance() {
+            warn!("failed to advance gps wal during replay: {}", err);
+        }
+    }
+}
+
 #[frb(opaque)]
 pub enum MapRendererProxy {
     MainMap,
@@ -71,6 +145,10 @@ pub enum MapRendererProxy {
 }
 
 impl MapRendererProxy {
+    /// Never panics across the FFI boundary: a panic while rendering (in
+    /// either variant; `Simple` owns its `MapRenderer` directly, with no
+    /// lock) or a storage failure comes back as a typed `FfiError` instead
+    /// of taking the whole app down with it.
     pub fn render_map_overlay(
         &mut self,
         zoom: f32,
@@ -78,50 +156,43 @@ impl MapRendererProxy {
         top: f64,
         right: f64,
         bottom: f64,
-    ) -> Option<RenderResult> {
+    ) -> std::result::Result<Option<RenderResult>, FfiError> {
         // TODO: right now the quality of zoom = 1 is really bad.
-        let zoom = max(zoom as i32, 2);
-
-        match self {
-            Self::MainMap => {
-                // TODO: now that we have `MapRendererProxy`, we should rethink the logic below.
-                let state = get();
-                let mut map_renderer = state.map_renderer.lock().unwrap();
-                if state.storage.main_map_renderer_need_to_reload() {
-                    *map_renderer = None;
-                }
-
-                map_renderer
-                    .get_or_insert_with(|| {
-                        // TODO: error handling?
-                        let journey_bitmap = state
-                            .storage
-                            .get_latest_bitmap_for_main_map_renderer()
-                            .unwrap();
-                        MapRenderer::new(journey_bitmap)
-                    })
-                    .maybe_render_map_overlay(zoom, left, top, right, bottom)
-            }
-            Self::Simple(map_renderer) => {
-                map_renderer.maybe_render_map_overlay(zoom, left, top, right, bottom)
-            }
-        }
+        let zoom = std::cmp::max(zoom as i32, 2);
+
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| match self {
+            Self::MainMap => call(|reply| Command::RenderOverlay {
+                zoom,
+                left,
+                top,
+                right,
+                bottom,
+                reply,
+            })?,
+            Self::Simple(map_renderer) => std::result::Result::Ok(
+                map_renderer.maybe_render_map_overlay(zoom, left, top, right, bottom),
+            ),
+        }))
+        .unwrap_or_else(|_| {
+            std::result::Result::Err(FfiError::RenderFailed(
+                "render_map_overlay panicked".to_string(),
+            ))
+        })
     }
 
-    pub fn reset_map_renderer(&mut self) {
-        match self {
-            Self::MainMap => {
-                let state = get();
-                let mut map_renderer = state.map_renderer.lock().unwrap();
-
-                if let Some(map_renderer) = &mut *map_renderer {
-                    map_renderer.reset();
-                }
-            }
+    pub fn reset_map_renderer(&mut self) -> std::result::Result<(), FfiError> {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| match self {
+            Self::MainMap => call(|reply| Command::ResetMainMapRenderer { reply })?,
             Self::Simple(map_renderer) => {
                 map_renderer.reset();
+                std::result::Result::Ok(())
             }
-        }
+        }))
+        .unwrap_or_else(|_| {
+            std::result::Result::Err(FfiError::RenderFailed(
+                "reset_map_renderer panicked".to_string(),
+            ))
+        })
     }
 }
 
@@ -131,115 +202,87 @@ pub fn get_map_renderer_proxy_for_main_map() -> MapRendererProxy {
 }
 
 pub fn get_map_renderer_proxy_for_journey(journey_id: &str) -> Result<MapRendererProxy> {
-    let journey_data = get()
-        .storage
-        .with_db_txn(|txn| txn.get_journey(journey_id))?;
-
-    let journey_bitmap = match journey_data {
-        JourneyData::Bitmap(bitmap) => bitmap,
-        JourneyData::Vector(vector) => {
-            let mut bitmap = JourneyBitmap::new();
-            merged_journey_builder::add_journey_vector_to_journey_bitmap(&mut bitmap, &vector);
-            bitmap
-        }
-    };
-
-    let map_renderer = MapRenderer::new(journey_bitmap);
-    Ok(MapRendererProxy::Simple(map_renderer))
-}
-
-pub fn on_location_update(
-    mut raw_data_list: Vec<gps_processor::RawData>,
-    recevied_timestamp_ms: i64,
-) {
-    let state = get();
-    // NOTE: On Android, we might recevied a batch of location updates that are out of order.
-    // Not very sure why yet.
-
-    // we need handle a batch in one go so we hold the lock for the whole time
-    let mut gps_processor = state.gps_processor.lock().unwrap();
-    let mut map_renderer = state.map_renderer.lock().unwrap();
-
-    raw_data_list.sort_by(|a, b| a.timestamp_ms.cmp(&b.timestamp_ms));
-    raw_data_list.into_iter().for_each(|raw_data| {
-        // TODO: more batching updates
-        let last_data = gps_processor.last_data();
-        let process_result = gps_processor.preprocess(&raw_data);
-        let line_to_add = match process_result {
-            ProcessResult::Ignore => None,
-            ProcessResult::NewSegment => Some((&raw_data, &raw_data)),
-            ProcessResult::Append => {
-                let start = last_data.as_ref().unwrap_or(&raw_data);
-                Some((start, &raw_data))
-            }
-        };
-        match map_renderer.as_mut() {
-            None => (),
-            Some(map_renderer) => match line_to_add {
-                None => (),
-                Some((start, end)) => {
-                    map_renderer.update(|journey_bitmap| {
-                        journey_bitmap.add_line(
-                            start.longitude,
-                            start.latitude,
-                            end.longitude,
-                            end.latitude,
-                        );
-                    });
-                }
-            },
-        }
-        state
-            .storage
-            .record_gps_data(&raw_data, process_result, recevied_timestamp_ms);
+    guarded(|| {
+        let journey_bitmap = call(|reply| Command::JourneyBitmapFor {
+            journey_id: journey_id.to_string(),
+            reply,
+        })??;
+        let map_renderer = MapRenderer::new(journey_bitmap);
+        Ok(MapRendererProxy::Simple(map_renderer))
+    })
+}
+
+pub fn on_location_update(raw_data_list: Vec<gps_processor::RawData>, recevied_timestamp_ms: i64) {
+    get().actor.send(Command::OnLocationUpdate {
+        raw_data_list,
+        recevied_timestamp_ms,
     });
 }
 
 pub fn list_all_raw_data() -> Vec<storage::RawDataFile> {
-    get().storage.list_all_raw_data()
+    call(|reply| Command::ListAllRawData { reply }).unwrap_or_else(|err| {
+        warn!("list_all_raw_data: {}", err);
+        Vec::new()
+    })
 }
 
 pub fn get_raw_data_mode() -> bool {
-    get().storage.get_raw_data_mode()
+    call(|reply| Command::GetRawDataMode { reply }).unwrap_or_else(|err| {
+        warn!("get_raw_data_mode: {}", err);
+        false
+    })
 }
 
 pub fn delete_raw_data_file(filename: String) -> Result<()> {
-    get().storage.delete_raw_data_file(filename)
+    guarded(|| call(|reply| Command::DeleteRawDataFile { filename, reply })?)
 }
 
 pub fn delete_journey(journey_id: &str) -> Result<()> {
-    get()
-        .storage
-        .with_db_txn(|txn| txn.delete_journey(journey_id))
+    guarded(|| {
+        call(|reply| Command::DeleteJourney {
+            journey_id: journey_id.to_string(),
+            reply,
+        })?
+    })
 }
 
 pub fn toggle_raw_data_mode(enable: bool) {
-    get().storage.toggle_raw_data_mode(enable)
+    get().actor.send(Command::ToggleRawDataMode { enable });
 }
 
 pub fn finalize_ongoing_journey() -> Result<bool> {
-    get()
-        .storage
-        .with_db_txn(|txn| txn.finalize_ongoing_journey())
+    guarded(|| call(|reply| Command::FinalizeJourney { reply })?)
 }
 
 pub fn try_auto_finalize_journy() -> Result<bool> {
-    get()
-        .storage
-        .with_db_txn(|txn| txn.try_auto_finalize_journy())
+    guarded(|| call(|reply| Command::TryAutoFinalizeJourney { reply })?)
 }
 
 pub fn list_all_journeys() -> Result<Vec<JourneyHeader>> {
-    get().storage.with_db_txn(|txn| txn.list_all_journeys())
+    guarded(|| call(|reply| Command::ListAllJourneys { reply })?)
 }
 
 pub fn generate_full_archive(target_filepath: String) -> Result<()> {
-    let mut file = File::create(target_filepath)?;
-    get()
-        .storage
-        .with_db_txn(|txn| archive::archive_all_as_zip(txn, &mut file))?;
-    drop(file);
-    Ok(())
+    // no job to cancel and nobody to report progress to when called synchronously.
+    generate_full_archive_cancellable(target_filepath, Arc::new(AtomicBool::new(false)), &|_| {})
+}
+
+/// Only the snapshot of what to archive (`SnapshotJourneysForArchive`) goes
+/// through the actor; the zip compression below touches no shared state,
+/// so it runs entirely on the calling thread instead of blocking the
+/// actor's command queue for however long that takes. See
+/// `actor::Command::SnapshotJourneysForArchive`.
+fn generate_full_archive_cancellable(
+    target_filepath: String,
+    cancelled: Arc<AtomicBool>,
+    on_progress: &(dyn Fn(f32) + Sync),
+) -> Result<()> {
+    let parallelism = get().archive_parallelism.load(Ordering::Relaxed);
+    guarded(|| {
+        let journeys = call(|reply| Command::SnapshotJourneysForArchive { reply })??;
+        let mut file = std::fs::File::create(&target_filepath)?;
+        archive::write_journeys_as_zip(&journeys, &mut file, parallelism, &cancelled, on_progress)
+    })
 }
 
 pub enum ExportType {
@@ -252,31 +295,170 @@ pub fn export_journey(
     journey_id: String,
     export_type: ExportType,
 ) -> Result<()> {
-    let journey_data = get()
-        .storage
-        .with_db_txn(|txn| txn.get_journey(&journey_id))?;
-    match journey_data {
-        JourneyData::Bitmap(_bitmap) => Err(anyhow!("Data type error")),
-        JourneyData::Vector(vector) => {
-            let mut file = File::create(target_filepath)?;
-            match export_type {
-                ExportType::GPX => {
-                    export_data::journey_vector_to_gpx_file(&vector, &mut file)?;
-                }
-                ExportType::KML => {
-                    export_data::journey_vector_to_kml_file(&vector, &mut file)?;
+    // no job to cancel when called synchronously.
+    export_journey_cancellable(
+        target_filepath,
+        journey_id,
+        export_type,
+        Arc::new(AtomicBool::new(false)),
+    )
+}
+
+/// As with `generate_full_archive_cancellable`, only the db lookup
+/// (`GetJourneyForExport`) goes through the actor; the GPX/KML encoding
+/// happens on the calling thread afterwards. `export_data`'s writers don't
+/// currently take a cancellation check of their own, so `cancelled` is
+/// only observed once, between the lookup and the write — a cancel
+/// requested after the write has already started still runs to
+/// completion.
+fn export_journey_cancellable(
+    target_filepath: String,
+    journey_id: String,
+    export_type: ExportType,
+    cancelled: Arc<AtomicBool>,
+) -> Result<()> {
+    guarded(|| {
+        let journey_data = call(|reply| Command::GetJourneyForExport { journey_id, reply })??;
+        if cancelled.load(Ordering::Acquire) {
+            bail!("cancelled");
+        }
+        match journey_data {
+            JourneyData::Bitmap(_bitmap) => Err(anyhow!("Data type error")),
+            JourneyData::Vector(vector) => {
+                let mut file = std::fs::File::create(&target_filepath)?;
+                match export_type {
+                    ExportType::GPX => export_data::journey_vector_to_gpx_file(&vector, &mut file)?,
+                    ExportType::KML => export_data::journey_vector_to_kml_file(&vector, &mut file)?,
                 }
+                Ok(())
             }
-            Ok(())
         }
-    }
+    })
+}
+
+/// Brings an external GPX/KML/FIT track into the library as one or more
+/// new journeys. `merge_tracks` controls whether a file with multiple
+/// tracks/segments becomes a single merged journey or one journey per
+/// track; either way, per-track parse problems come back as `warnings`
+/// rather than failing the whole import.
+pub fn import_journey(
+    source_filepath: String,
+    import_type: ImportType,
+    merge_tracks: bool,
+) -> Result<ImportResult> {
+    guarded(|| {
+        call(|reply| Command::ImportJourney {
+            source_filepath,
+            import_type,
+            merge_tracks,
+            reply,
+        })?
+    })
 }
 
 pub fn recover_from_archive(zip_file_path: String) -> Result<()> {
-    get()
-        .storage
-        .with_db_txn(|txn| archive::recover_archive_file(txn, &zip_file_path))?;
-    Ok(())
+    // no job to cancel and nobody to report progress to when called synchronously.
+    recover_from_archive_cancellable(zip_file_path, Arc::new(AtomicBool::new(false)), &|_| {})
+}
+
+/// Symmetric to `generate_full_archive_cancellable`: the decompression
+/// (`archive::read_journeys_from_zip`) runs entirely on the calling thread
+/// since it needs no shared state, and only the final commit
+/// (`CommitRecoveredJourneys`) goes through the actor.
+fn recover_from_archive_cancellable(
+    zip_file_path: String,
+    cancelled: Arc<AtomicBool>,
+    on_progress: &(dyn Fn(f32) + Sync),
+) -> Result<()> {
+    let parallelism = get().archive_parallelism.load(Ordering::Relaxed);
+    guarded(|| {
+        let journeys =
+            archive::read_journeys_from_zip(&zip_file_path, parallelism, &cancelled, on_progress)?;
+        call(|reply| Command::CommitRecoveredJourneys {
+            journeys,
+            cancelled,
+            reply,
+        })?
+    })
+}
+
+/// Sets how many threads `generate_full_archive`/`recover_from_archive`
+/// (and their `enqueue_*` counterparts) may use to serialize/deserialize
+/// journeys in parallel. Useful for the mobile client to throttle down
+/// under thermal or battery pressure; defaults to the number of cores.
+pub fn set_archive_parallelism(n: usize) {
+    get().archive_parallelism.store(n.max(1), Ordering::Relaxed);
+}
+
+/// Same as `generate_full_archive`, but runs on a background thread and
+/// returns immediately with a job ID; subscribe via `subscribe_job` to get
+/// progress, or `cancel_job` to abort and remove the partial output file.
+/// Only the brief db snapshot goes through the actor (see
+/// `generate_full_archive_cancellable`), so a long archive never blocks
+/// other FFI calls the way running the whole thing on the actor used to.
+pub fn enqueue_full_archive(target_filepath: String) -> JobId {
+    let target_filepath_for_abort = target_filepath.clone();
+    get().job_manager.enqueue(
+        move |ctx| {
+            ctx.set_progress(0.0, "archiving");
+            // wiring the job's own cancellation flag through means a
+            // cancel request is observed per-journey inside the archive
+            // loop, not only after the whole archive already finished.
+            generate_full_archive_cancellable(target_filepath, ctx.cancellation_flag(), &|percent| {
+                ctx.set_progress(percent, "archiving");
+            })
+        },
+        move || {
+            let _ = std::fs::remove_file(&target_filepath_for_abort);
+        },
+    )
+}
+
+/// Background-job variant of `recover_from_archive`. See `enqueue_full_archive`.
+pub fn enqueue_recover_from_archive(zip_file_path: String) -> JobId {
+    get().job_manager.enqueue(
+        move |ctx| {
+            ctx.set_progress(0.0, "recovering");
+            recover_from_archive_cancellable(zip_file_path, ctx.cancellation_flag(), &|percent| {
+                ctx.set_progress(percent, "recovering");
+            })
+        },
+        || {},
+    )
+}
+
+/// Background-job variant of `export_journey`. See `enqueue_full_archive`.
+/// Unlike the archive jobs above, a cancel here is only observed once,
+/// before the GPX/KML write starts (see `export_journey_cancellable`) —
+/// `export_data`'s writers aren't wired up to check cancellation per point.
+pub fn enqueue_export_journey(
+    target_filepath: String,
+    journey_id: String,
+    export_type: ExportType,
+) -> JobId {
+    let target_filepath_for_abort = target_filepath.clone();
+    get().job_manager.enqueue(
+        move |ctx| {
+            ctx.set_progress(0.0, "exporting");
+            export_journey_cancellable(
+                target_filepath,
+                journey_id,
+                export_type,
+                ctx.cancellation_flag(),
+            )
+        },
+        move || {
+            let _ = std::fs::remove_file(&target_filepath_for_abort);
+        },
+    )
+}
+
+pub fn cancel_job(job_id: JobId) {
+    get().job_manager.cancel(job_id);
+}
+
+pub fn subscribe_job(job_id: JobId, sink: StreamSink<JobProgress>) {
+    get().job_manager.subscribe(job_id, sink);
 }
 
 #[derive(Debug)]
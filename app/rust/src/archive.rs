@@ -0,0 +1,119 @@
+use std::io::{Read, Seek, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::Result;
+use rayon::prelude::*;
+
+use crate::journey_data::JourneyData;
+use crate::storage::Txn;
+
+/// The quick, db-bound half of `generate_full_archive`: pulls every
+/// journey's data out of the current transaction into memory so the
+/// caller can compress it on its own time. Kept separate from
+/// `write_journeys_as_zip` so that call can run off the thread that owns
+/// `Storage` (see `actor::Command::SnapshotJourneysForArchive`).
+pub fn collect_journeys_for_archive(txn: &mut Txn) -> Result<Vec<(String, JourneyData)>> {
+    let headers = txn.list_all_journeys()?;
+    headers
+        .into_iter()
+        .map(|header| {
+            let data = txn.get_journey(&header.id)?;
+            Ok((header.id, data))
+        })
+        .collect()
+}
+
+/// Serializes every journey into an in-memory, compressed buffer in
+/// parallel (bounded by `parallelism`), then streams the finished buffers
+/// into the zip on this thread, since `ZipWriter` isn't `Sync` and the
+/// writer itself has to stay single-threaded. `cancelled` is polled once
+/// per journey (not just once for the whole call), so a cancel request
+/// lands well before the last journey would otherwise finish. `on_progress`
+/// is called after each journey finishes with the fraction of the total
+/// done so far; it may be called concurrently from several rayon worker
+/// threads, so it must be `Sync`.
+pub fn write_journeys_as_zip(
+    journeys: &[(String, JourneyData)],
+    writer: &mut (impl Write + Seek),
+    parallelism: usize,
+    cancelled: &AtomicBool,
+    on_progress: &(dyn Fn(f32) + Sync),
+) -> Result<()> {
+    let total = journeys.len().max(1);
+    let completed = AtomicUsize::new(0);
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(parallelism.max(1))
+        .build()?;
+
+    let buffers = pool.install(|| {
+        journeys
+            .par_iter()
+            .map(|(id, data)| -> Result<(String, Vec<u8>)> {
+                if cancelled.load(Ordering::Acquire) {
+                    bail!("cancelled");
+                }
+                let mut buf = Vec::new();
+                data.serialize_into(&mut buf)?;
+                let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                on_progress(done as f32 / total as f32);
+                Ok((id.clone(), buf))
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let mut zip = zip::ZipWriter::new(writer);
+    for (id, buf) in buffers {
+        zip.start_file(format!("{id}.journey"), zip::write::FileOptions::default())?;
+        zip.write_all(&buf)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Symmetric to `write_journeys_as_zip`: reads and decompresses the zip
+/// entries one after another on this thread, then deserializes them in
+/// parallel. It touches no shared state, so it can run off the thread
+/// that owns `Storage`. The caller commits the result via
+/// `actor::Command::CommitRecoveredJourneys`. `cancelled`/`on_progress`
+/// behave the same as in `write_journeys_as_zip`.
+pub fn read_journeys_from_zip(
+    zip_file_path: &str,
+    parallelism: usize,
+    cancelled: &AtomicBool,
+    on_progress: &(dyn Fn(f32) + Sync),
+) -> Result<Vec<(String, JourneyData)>> {
+    let file = std::fs::File::open(zip_file_path)?;
+    let mut zip = zip::ZipArchive::new(file)?;
+
+    let mut raw_entries = Vec::with_capacity(zip.len());
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let id = entry.name().trim_end_matches(".journey").to_string();
+        let mut buf = Vec::new();
+        entry.read_to_end(&mut buf)?;
+        raw_entries.push((id, buf));
+    }
+
+    let total = raw_entries.len().max(1);
+    let completed = AtomicUsize::new(0);
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(parallelism.max(1))
+        .build()?;
+
+    pool.install(|| {
+        raw_entries
+            .par_iter()
+            .map(|(id, buf)| -> Result<(String, JourneyData)> {
+                if cancelled.load(Ordering::Acquire) {
+                    bail!("cancelled");
+                }
+                let data = JourneyData::deserialize_from(buf.as_slice())?;
+                let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                on_progress(done as f32 / total as f32);
+                Ok((id.clone(), data))
+            })
+            .collect::<Result<Vec<_>>>()
+    })
+}
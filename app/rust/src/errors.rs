@@ -0,0 +1,14 @@
+use thiserror::Error;
+
+/// Typed errors that can cross the `flutter_rust_bridge` boundary from the
+/// render/state-access layer, so the Dart side always gets a definite
+/// outcome instead of a dropped callback or a hard crash.
+#[derive(Error, Debug)]
+pub enum FfiError {
+    #[error("corrupt bitmap: {0}")]
+    CorruptBitmap(String),
+    #[error("render failed: {0}")]
+    RenderFailed(String),
+    #[error("the native worker thread is not responding (it may have panicked): {0}")]
+    ActorUnavailable(String),
+}
@@ -0,0 +1,276 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::gps_processor::RawData;
+
+/// A single not-yet-committed batch as handed to `on_location_update`,
+/// recovered verbatim so replay can go through the exact same processing
+/// path as a live update.
+pub struct WalRecord {
+    pub raw_data_list: Vec<RawData>,
+    pub received_timestamp_ms: i64,
+}
+
+/// Append-only, length-prefixed write-ahead log for incoming GPS batches.
+///
+/// A batch is appended (and fsync'd) *before* it is written to the journey
+/// database; only once the corresponding db transaction commits is the
+/// record removed from the front of the log via `advance`. If the process
+/// is killed in between, the batch is still on disk and `pending_records`
+/// will hand it back on the next start so it can be replayed through the
+/// same code path, so no fix is ever lost.
+///
+/// This module only guarantees that: it does not itself guarantee that
+/// replaying an already-committed batch is harmless. That depends on
+/// `Storage::record_gps_data` treating a point with a `timestamp_ms` it has
+/// already seen as a no-op, which is an invariant of the storage layer,
+/// not something exercised by this file's tests.
+pub struct GpsWal {
+    path: PathBuf,
+    file: File,
+}
+
+impl GpsWal {
+    pub fn open(support_dir: &str) -> Result<Self> {
+        let path = Path::new(support_dir).join("gps.wal");
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        Ok(GpsWal { path, file })
+    }
+
+    /// Reads every record currently sitting in the log, oldest first.
+    pub fn pending_records(&self) -> Result<Vec<WalRecord>> {
+        let mut bytes = Vec::new();
+        let mut file = File::open(&self.path)?;
+        file.read_to_end(&mut bytes)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0usize;
+        while offset < bytes.len() {
+            if offset + 4 > bytes.len() {
+                // trailing partial write from a crash mid-append; stop here.
+                break;
+            }
+            let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            offset += 4;
+            if offset + len > bytes.len() {
+                break;
+            }
+            let record: (Vec<RawData>, i64) = bincode::deserialize(&bytes[offset..offset + len])?;
+            records.push(WalRecord {
+                raw_data_list: record.0,
+                received_timestamp_ms: record.1,
+            });
+            offset += len;
+        }
+        Ok(records)
+    }
+
+    /// Appends `raw_data_list` (already sorted by the caller) to the log
+    /// and fsyncs before returning, so the batch survives a crash even if
+    /// the subsequent db write never happens.
+    pub fn append(&mut self, raw_data_list: &[RawData], received_timestamp_ms: i64) -> Result<()> {
+        let encoded = bincode::serialize(&(raw_data_list.to_vec(), received_timestamp_ms))?;
+        self.file
+            .write_all(&(encoded.len() as u32).to_le_bytes())?;
+        self.file.write_all(&encoded)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// Drops the oldest record from the log. Must only be called after the
+    /// matching db transaction has committed.
+    ///
+    /// Writes the remaining records to a sibling temp file, fsyncs it, then
+    /// renames it over the log (atomic on the filesystems this runs on). A
+    /// crash at any point before the rename leaves the original log
+    /// untouched, so a batch is never lost to a half-finished `advance` —
+    /// only ever replayed again, which `pending_records`/replay already
+    /// tolerate.
+    pub fn advance(&mut self) -> Result<()> {
+        let remaining = {
+            let records = self.pending_records()?;
+            records.into_iter().skip(1).collect::<Vec<_>>()
+        };
+
+        let tmp_path = self.path.with_extension("wal.tmp");
+        {
+            let mut tmp_file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            for record in &remaining {
+                let encoded =
+                    bincode::serialize(&(record.raw_data_list.clone(), record.received_timestamp_ms))?;
+                tmp_file.write_all(&(encoded.len() as u32).to_le_bytes())?;
+                tmp_file.write_all(&encoded)?;
+            }
+            tmp_file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        // `self.file` was opened against the pre-rename inode; reopen it
+        // against the (now-replaced) path so future appends land in the
+        // file we just renamed into place.
+        self.file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    /// A fresh, empty directory for one test to use as `support_dir`, torn
+    /// down when the returned guard drops.
+    struct TempSupportDir(PathBuf);
+
+    impl TempSupportDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "memolanes_gps_wal_test_{}_{}_{:?}",
+                name,
+                std::process::id(),
+                std::thread::current().id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempSupportDir(dir)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempSupportDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn pending_records_is_empty_for_a_fresh_wal() {
+        let dir = TempSupportDir::new("fresh");
+        let wal = GpsWal::open(dir.path()).unwrap();
+        assert!(wal.pending_records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn append_then_advance_round_trips_in_order() {
+        let dir = TempSupportDir::new("round_trip");
+        let mut wal = GpsWal::open(dir.path()).unwrap();
+
+        wal.append(&[], 1).unwrap();
+        wal.append(&[], 2).unwrap();
+        wal.append(&[], 3).unwrap();
+
+        let records = wal.pending_records().unwrap();
+        let timestamps: Vec<i64> = records.iter().map(|r| r.received_timestamp_ms).collect();
+        assert_eq!(timestamps, vec![1, 2, 3]);
+
+        wal.advance().unwrap();
+        let timestamps: Vec<i64> = wal
+            .pending_records()
+            .unwrap()
+            .iter()
+            .map(|r| r.received_timestamp_ms)
+            .collect();
+        assert_eq!(timestamps, vec![2, 3]);
+    }
+
+    #[test]
+    fn advancing_past_the_last_record_leaves_the_log_empty() {
+        let dir = TempSupportDir::new("drain");
+        let mut wal = GpsWal::open(dir.path()).unwrap();
+
+        wal.append(&[], 42).unwrap();
+        wal.advance().unwrap();
+
+        assert!(wal.pending_records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_record_survives_being_reopened() {
+        let dir = TempSupportDir::new("reopen");
+        {
+            let mut wal = GpsWal::open(dir.path()).unwrap();
+            wal.append(&[], 7).unwrap();
+        }
+        let wal = GpsWal::open(dir.path()).unwrap();
+        let records = wal.pending_records().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].received_timestamp_ms, 7);
+    }
+
+    #[test]
+    fn a_trailing_partial_write_from_a_crash_is_ignored() {
+        let dir = TempSupportDir::new("partial_write");
+        let mut wal = GpsWal::open(dir.path()).unwrap();
+        wal.append(&[], 1).unwrap();
+        wal.append(&[], 2).unwrap();
+
+        // Simulate the process being killed mid-`append`: a length prefix
+        // was written (claiming more bytes than actually follow), but the
+        // record body never made it to disk.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join("gps.wal"))
+            .unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        file.sync_all().unwrap();
+
+        let records = wal.pending_records().unwrap();
+        let timestamps: Vec<i64> = records.iter().map(|r| r.received_timestamp_ms).collect();
+        assert_eq!(timestamps, vec![1, 2]);
+    }
+
+    #[test]
+    fn a_crash_before_the_advance_rename_leaves_the_original_log_intact() {
+        let dir = TempSupportDir::new("advance_crash");
+        let mut wal = GpsWal::open(dir.path()).unwrap();
+        wal.append(&[], 1).unwrap();
+        wal.append(&[], 2).unwrap();
+
+        // Simulate `advance` getting killed after it wrote (and fsync'd)
+        // the replacement file but before the rename landed: the
+        // `.wal.tmp` sibling exists, but `gps.wal` itself is untouched.
+        let tmp_path = dir.0.join("gps.wal.tmp");
+        let mut tmp_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .unwrap();
+        let encoded = bincode::serialize(&(Vec::<crate::gps_processor::RawData>::new(), 2i64)).unwrap();
+        tmp_file
+            .write_all(&(encoded.len() as u32).to_le_bytes())
+            .unwrap();
+        tmp_file.write_all(&encoded).unwrap();
+        tmp_file.sync_all().unwrap();
+
+        // The real `advance` never ran, so reading the log still sees both
+        // original records, not the half-applied `.tmp` content.
+        let timestamps: Vec<i64> = wal
+            .pending_records()
+            .unwrap()
+            .iter()
+            .map(|r| r.received_timestamp_ms)
+            .collect();
+        assert_eq!(timestamps, vec![1, 2]);
+    }
+}
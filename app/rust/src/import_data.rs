@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::journey_bitmap::JourneyBitmap;
+use crate::journey_data::JourneyData;
+use crate::journey_vector::{self, JourneyVector};
+use crate::merged_journey_builder;
+use crate::storage::Storage;
+
+/// Inverse of `ExportType`: the on-disk format of the track being brought
+/// in from another app or GPS watch.
+#[derive(Clone, Copy, Debug)]
+pub enum ImportType {
+    Gpx = 0,
+    Kml = 1,
+    Fit = 2,
+}
+
+/// A parse problem (bad timestamp, missing coordinate, ...) scoped to one
+/// track/segment within the source file. Import keeps going past these
+/// instead of aborting the whole file.
+#[derive(Debug)]
+pub struct ImportWarning {
+    pub track_index: usize,
+    pub message: String,
+}
+
+pub struct ImportResult {
+    /// one id per imported journey: a single id if `merge_tracks` was set
+    /// (or the file only had one track), otherwise one per source track
+    /// that parsed and committed successfully.
+    pub journey_ids: Vec<String>,
+    pub warnings: Vec<ImportWarning>,
+}
+
+/// Parses `source_filepath` as `import_type` and commits the result as one
+/// or more new journeys, returning their ids. Per-track parse failures
+/// (e.g. a segment with an unparsable timestamp) are reported as
+/// `ImportWarning`s rather than failing the whole import, as long as at
+/// least one track parsed successfully.
+pub fn import_journey(
+    storage: &Storage,
+    source_filepath: &str,
+    import_type: ImportType,
+    merge_tracks: bool,
+) -> Result<ImportResult> {
+    let raw_tracks = match import_type {
+        ImportType::Gpx => parse_gpx(source_filepath)?,
+        ImportType::Kml => parse_kml(source_filepath)?,
+        ImportType::Fit => parse_fit(source_filepath)?,
+    };
+
+    let mut vectors = Vec::new();
+    let mut warnings = Vec::new();
+    for (track_index, track) in raw_tracks.into_iter().enumerate() {
+        match track {
+            Result::Ok(vector) => vectors.push((track_index, vector)),
+            Result::Err(err) => warnings.push(ImportWarning {
+                track_index,
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    if vectors.is_empty() {
+        return Err(anyhow!(
+            "no usable tracks found in {} ({} warning(s))",
+            source_filepath,
+            warnings.len()
+        ));
+    }
+
+    // Each track is committed in its own `with_db_txn` call, so a failure
+    // partway through `merge_tracks == false` must not abort via `?`: that
+    // would leave the already-committed tracks as orphan journeys with no
+    // way to know about them, while reporting the whole import as failed.
+    // Instead a commit failure is folded into `warnings` exactly like a
+    // parse failure, and only an import where *nothing* made it in is an
+    // error.
+    let journey_ids = if merge_tracks {
+        let merged =
+            journey_vector::merge_journey_vectors(vectors.into_iter().map(|(_, v)| v).collect());
+        vec![commit_journey_vector(storage, merged)?]
+    } else {
+        let mut journey_ids = Vec::new();
+        for (track_index, vector) in vectors {
+            match commit_journey_vector(storage, vector) {
+                Result::Ok(journey_id) => journey_ids.push(journey_id),
+                Result::Err(err) => warnings.push(ImportWarning {
+                    track_index,
+                    message: err.to_string(),
+                }),
+            }
+        }
+        if journey_ids.is_empty() {
+            return Err(anyhow!(
+                "no usable tracks found in {} ({} warning(s))",
+                source_filepath,
+                warnings.len()
+            ));
+        }
+        journey_ids
+    };
+
+    Ok(ImportResult {
+        journey_ids,
+        warnings,
+    })
+}
+
+// Building the bitmap here (same path `get_map_renderer_proxy_for_journey`
+// uses at render time) catches a degenerate import early, e.g. a track
+// with a single point and nothing to draw a line between.
+fn commit_journey_vector(storage: &Storage, vector: JourneyVector) -> Result<String> {
+    let mut bitmap = JourneyBitmap::new();
+    merged_journey_builder::add_journey_vector_to_journey_bitmap(&mut bitmap, &vector);
+    if bitmap.is_empty() {
+        return Err(anyhow!("imported track has no usable line segments"));
+    }
+    storage.with_db_txn(|txn| txn.create_and_insert_journey(JourneyData::Vector(vector)))
+}
+
+fn parse_gpx(source_filepath: &str) -> Result<Vec<Result<JourneyVector>>> {
+    let file = File::open(source_filepath).context("failed to open gpx file")?;
+    let gpx = gpx::read(BufReader::new(file)).context("failed to parse gpx file")?;
+    Ok(gpx
+        .tracks
+        .into_iter()
+        .map(JourneyVector::try_from_gpx_track)
+        .collect())
+}
+
+fn parse_kml(source_filepath: &str) -> Result<Vec<Result<JourneyVector>>> {
+    let file = File::open(source_filepath).context("failed to open kml file")?;
+    let kml = kml::KmlReader::<_, f64>::from_reader(BufReader::new(file))
+        .read()
+        .context("failed to parse kml file")?;
+    Ok(JourneyVector::tracks_from_kml(kml))
+}
+
+fn parse_fit(source_filepath: &str) -> Result<Vec<Result<JourneyVector>>> {
+    let file = File::open(source_filepath).context("failed to open fit file")?;
+    let records = fitparser::from_reader(&mut BufReader::new(file))
+        .context("failed to parse fit file")?;
+    Ok(vec![JourneyVector::try_from_fit_records(records)])
+}
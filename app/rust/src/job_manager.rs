@@ -0,0 +1,313 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use flutter_rust_bridge::frb;
+use flutter_rust_bridge::StreamSink;
+
+#[frb(opaque)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct JobId(u64);
+
+#[derive(Clone, Debug)]
+pub enum JobProgress {
+    Queued,
+    Running { percent: f32, stage: String },
+    Completed,
+    Failed { error: String },
+    Cancelled,
+}
+
+/// Handed to the closure running on the worker thread so it can report
+/// progress and check for cancellation between units of work (e.g. one
+/// journey at a time).
+pub struct JobContext {
+    cancelled: Arc<AtomicBool>,
+    progress: Arc<Mutex<JobProgress>>,
+    sinks: Arc<Mutex<Vec<StreamSink<JobProgress>>>>,
+}
+
+impl JobContext {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// The flag backing `is_cancelled`, for handing to work that needs to
+    /// poll cancellation itself from inside a tighter loop (e.g. once per
+    /// journey in an archive/recover) instead of only checking once the
+    /// whole operation has already returned.
+    pub fn cancellation_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    pub fn set_progress(&self, percent: f32, stage: impl Into<String>) {
+        self.publish(JobProgress::Running {
+            percent,
+            stage: stage.into(),
+        });
+    }
+
+    fn publish(&self, progress: JobProgress) {
+        *self.progress.lock().unwrap() = progress.clone();
+        self.sinks
+            .lock()
+            .unwrap()
+            .retain(|sink| sink.add(progress.clone()).is_ok());
+    }
+}
+
+/// Best-effort extraction of a human-readable message from a `catch_unwind`
+/// payload; most panics carry a `&str` or `String` via `panic!`/`.unwrap()`.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+struct Job {
+    cancelled: Arc<AtomicBool>,
+    progress: Arc<Mutex<JobProgress>>,
+    sinks: Arc<Mutex<Vec<StreamSink<JobProgress>>>>,
+}
+
+/// The terminal `JobProgress` of recently-finished jobs, kept around after
+/// their `Job` entry is dropped so a `subscribe_job` that arrives just
+/// after completion still sees the real outcome (in particular `Failed`)
+/// instead of `subscribe` having to guess. Bounded so a long-running app
+/// doesn't grow this without limit; a job older than the cap is
+/// indistinguishable from one that never existed, same as before this
+/// cache existed at all.
+const FINISHED_JOBS_CAPACITY: usize = 128;
+
+#[derive(Default)]
+struct FinishedJobs {
+    progress: HashMap<JobId, JobProgress>,
+    order: VecDeque<JobId>,
+}
+
+impl FinishedJobs {
+    fn record(&mut self, id: JobId, progress: JobProgress) {
+        self.progress.insert(id, progress);
+        self.order.push_back(id);
+        if self.order.len() > FINISHED_JOBS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.progress.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Runs long operations (archive generation/recovery, journey export) on a
+/// background thread instead of blocking the FFI call, and lets the UI
+/// subscribe to a stream of `JobProgress` updates and cancel mid-flight.
+#[frb(opaque)]
+pub struct JobManager {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<JobId, Job>>,
+    finished: Mutex<FinishedJobs>,
+}
+
+impl JobManager {
+    pub fn new() -> Self {
+        JobManager {
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(HashMap::new()),
+            finished: Mutex::new(FinishedJobs::default()),
+        }
+    }
+
+    /// Spawns `work` on a dedicated thread and returns a `JobId` the caller
+    /// can use to subscribe to progress or cancel. If `work` fails or is
+    /// cancelled, `on_abort` is invoked to clean up any partial output.
+    pub fn enqueue<F, C>(self: &Arc<Self>, work: F, on_abort: C) -> JobId
+    where
+        F: FnOnce(&JobContext) -> anyhow::Result<()> + Send + 'static,
+        C: FnOnce() + Send + 'static,
+    {
+        let id = JobId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(Mutex::new(JobProgress::Queued));
+        let sinks = Arc::new(Mutex::new(Vec::new()));
+
+        self.jobs.lock().unwrap().insert(
+            id,
+            Job {
+                cancelled: cancelled.clone(),
+                progress: progress.clone(),
+                sinks: sinks.clone(),
+            },
+        );
+
+        let manager = self.clone();
+        thread::spawn(move || {
+            let ctx = JobContext {
+                cancelled: cancelled.clone(),
+                progress: progress.clone(),
+                sinks: sinks.clone(),
+            };
+            // A panic inside `work` must still leave the job in a terminal
+            // state: otherwise the thread dies before `ctx.publish` and the
+            // `jobs` map cleanup run, and the job is stuck "Running"
+            // forever with its `jobs` entry leaked for the process
+            // lifetime.
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| work(&ctx)))
+                .unwrap_or_else(|payload| Err(anyhow!("job panicked: {}", panic_message(&payload))));
+            let final_progress = if cancelled.load(Ordering::Acquire) {
+                on_abort();
+                JobProgress::Cancelled
+            } else {
+                match result {
+                    Result::Ok(()) => JobProgress::Completed,
+                    Result::Err(err) => {
+                        on_abort();
+                        JobProgress::Failed {
+                            error: err.to_string(),
+                        }
+                    }
+                }
+            };
+            ctx.publish(final_progress.clone());
+            // keep the terminal outcome around briefly so a late
+            // `subscribe_job` still observes it instead of a made-up one.
+            // Recorded before the `jobs` entry is removed, so there is no
+            // moment when the job is in neither map.
+            manager.finished.lock().unwrap().record(id, final_progress);
+            manager.jobs.lock().unwrap().remove(&id);
+        });
+
+        id
+    }
+
+    pub fn cancel(&self, id: JobId) {
+        if let Some(job) = self.jobs.lock().unwrap().get(&id) {
+            job.cancelled.store(true, Ordering::Release);
+        }
+    }
+
+    /// Registers `sink` to receive all future progress updates for `id`,
+    /// and immediately replays the last known state so the UI doesn't have
+    /// to wait for the next update to render something.
+    pub fn subscribe(&self, id: JobId, sink: StreamSink<JobProgress>) {
+        {
+            let jobs = self.jobs.lock().unwrap();
+            if let Some(job) = jobs.get(&id) {
+                let _ = sink.add(job.progress.lock().unwrap().clone());
+                job.sinks.lock().unwrap().push(sink);
+                return;
+            }
+        }
+
+        // Not active: either it already finished (in which case `finished`
+        // has its real, possibly-`Failed`/`Cancelled` terminal progress) or
+        // the id is unknown/too old to still be cached. In the latter case
+        // there is nothing honest to replay, so the sink is simply left
+        // without an initial value rather than lying with `Completed`.
+        if let Some(progress) = self.finished.lock().unwrap().progress.get(&id) {
+            let _ = sink.add(progress.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for condition");
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    #[test]
+    fn a_successful_job_ends_up_completed() {
+        let manager = Arc::new(JobManager::new());
+        let id = manager.enqueue(|_ctx| Result::Ok(()), || {});
+
+        wait_until(|| manager.jobs.lock().unwrap().is_empty());
+
+        let finished = manager.finished.lock().unwrap();
+        assert!(matches!(finished.progress.get(&id), Some(JobProgress::Completed)));
+    }
+
+    #[test]
+    fn a_failing_job_ends_up_failed_and_runs_on_abort() {
+        let manager = Arc::new(JobManager::new());
+        let aborted = Arc::new(AtomicBool::new(false));
+        let aborted_clone = aborted.clone();
+        let id = manager.enqueue(
+            |_ctx| Err(anyhow!("boom")),
+            move || aborted_clone.store(true, Ordering::Release),
+        );
+
+        wait_until(|| manager.jobs.lock().unwrap().is_empty());
+
+        assert!(aborted.load(Ordering::Acquire));
+        let finished = manager.finished.lock().unwrap();
+        assert!(matches!(
+            finished.progress.get(&id),
+            Some(JobProgress::Failed { .. })
+        ));
+    }
+
+    #[test]
+    fn a_panicking_job_ends_up_failed_instead_of_leaking_the_job() {
+        let manager = Arc::new(JobManager::new());
+        let id = manager.enqueue(
+            |_ctx| -> anyhow::Result<()> { panic!("deliberate test panic") },
+            || {},
+        );
+
+        wait_until(|| manager.jobs.lock().unwrap().is_empty());
+
+        let finished = manager.finished.lock().unwrap();
+        assert!(matches!(
+            finished.progress.get(&id),
+            Some(JobProgress::Failed { .. })
+        ));
+    }
+
+    #[test]
+    fn cancelling_a_running_job_stops_it_and_runs_on_abort() {
+        let manager = Arc::new(JobManager::new());
+        let aborted = Arc::new(AtomicBool::new(false));
+        let aborted_clone = aborted.clone();
+        let id = manager.enqueue(
+            |ctx| {
+                while !ctx.is_cancelled() {
+                    thread::sleep(Duration::from_millis(2));
+                }
+                Result::Ok(())
+            },
+            move || aborted_clone.store(true, Ordering::Release),
+        );
+
+        manager.cancel(id);
+        wait_until(|| manager.jobs.lock().unwrap().is_empty());
+
+        assert!(aborted.load(Ordering::Acquire));
+        let finished = manager.finished.lock().unwrap();
+        assert!(matches!(finished.progress.get(&id), Some(JobProgress::Cancelled)));
+    }
+
+    #[test]
+    fn finished_jobs_cache_evicts_the_oldest_past_capacity() {
+        let mut cache = FinishedJobs::default();
+        for i in 0..(FINISHED_JOBS_CAPACITY as u64 + 10) {
+            cache.record(JobId(i), JobProgress::Completed);
+        }
+        assert_eq!(cache.progress.len(), FINISHED_JOBS_CAPACITY);
+        assert!(!cache.progress.contains_key(&JobId(0)));
+        assert!(cache
+            .progress
+            .contains_key(&JobId(FINISHED_JOBS_CAPACITY as u64 + 9)));
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{anyhow, Result};
+
+/// One GPS fix within a track. `timestamp_ms` is `None` when the source
+/// format didn't carry a timestamp for this point (some KML tracks don't).
+#[derive(Clone, Debug)]
+pub struct TrackPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub timestamp_ms: Option<i64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackSegment {
+    pub track_points: Vec<TrackPoint>,
+}
+
+/// The vector (as opposed to bitmap) representation of a journey: the raw
+/// track, kept around so it can be re-exported or re-rendered at a
+/// different resolution.
+#[derive(Clone, Debug, Default)]
+pub struct JourneyVector {
+    pub track_segments: Vec<TrackSegment>,
+}
+
+impl JourneyVector {
+    pub fn try_from_gpx_track(track: gpx::Track) -> Result<Self> {
+        let track_segments = track
+            .segments
+            .into_iter()
+            .map(|segment| TrackSegment {
+                track_points: segment
+                    .points
+                    .into_iter()
+                    .map(|point| {
+                        let (longitude, latitude) = point.point().x_y();
+                        TrackPoint {
+                            latitude,
+                            longitude,
+                            timestamp_ms: point.time.map(gpx_time_to_millis),
+                        }
+                    })
+                    .collect(),
+            })
+            .filter(|segment: &TrackSegment| !segment.track_points.is_empty())
+            .collect::<Vec<_>>();
+
+        if track_segments.is_empty() {
+            return Err(anyhow!("track has no points"));
+        }
+        Ok(JourneyVector { track_segments })
+    }
+
+    /// KML files can hold several independent `Placemark` tracks; each
+    /// becomes its own (fallible) `JourneyVector` so a bad placemark
+    /// doesn't take the rest of the file down with it.
+    pub fn tracks_from_kml(kml: kml::Kml) -> Vec<Result<JourneyVector>> {
+        kml_placemarks(&kml)
+            .into_iter()
+            .map(|placemark| {
+                // Plain KML (as opposed to the `gx:Track` extension, which
+                // this parser doesn't support) only carries one timestamp
+                // per placemark via `<TimeStamp><when>`, not one per
+                // vertex; apply it to every point in the track.
+                let timestamp_ms = kml_placemark_timestamp_ms(placemark);
+                let track_points = kml_placemark_coords(placemark)
+                    .into_iter()
+                    .map(|(longitude, latitude)| TrackPoint {
+                        latitude,
+                        longitude,
+                        timestamp_ms,
+                    })
+                    .collect::<Vec<_>>();
+                if track_points.is_empty() {
+                    return Err(anyhow!("placemark has no coordinates"));
+                }
+                Ok(JourneyVector {
+                    track_segments: vec![TrackSegment { track_points }],
+                })
+            })
+            .collect()
+    }
+
+    pub fn try_from_fit_records(records: Vec<fitparser::FitDataRecord>) -> Result<JourneyVector> {
+        let track_points = records
+            .iter()
+            .filter(|record| record.kind() == fitparser::profile::MesgNum::Record)
+            .filter_map(|record| {
+                let mut latitude = None;
+                let mut longitude = None;
+                let mut timestamp_ms = None;
+                for field in record.fields() {
+                    match field.name() {
+                        "position_lat" => latitude = field.value().clone().try_into().ok(),
+                        "position_long" => longitude = field.value().clone().try_into().ok(),
+                        "timestamp" => match field.value().clone().try_into() {
+                            Result::Ok(timestamp) => {
+                                let timestamp: chrono::DateTime<chrono::Utc> = timestamp;
+                                timestamp_ms = Some(timestamp.timestamp_millis());
+                            }
+                            Result::Err(_) => {
+                                warn!("fit record has an unparsable timestamp field, leaving it unset");
+                            }
+                        },
+                        _ => {}
+                    }
+                }
+                Some(TrackPoint {
+                    latitude: latitude?,
+                    longitude: longitude?,
+                    timestamp_ms,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if track_points.is_empty() {
+            return Err(anyhow!("no record messages with a position found"));
+        }
+        Ok(JourneyVector {
+            track_segments: vec![TrackSegment { track_points }],
+        })
+    }
+}
+
+/// `gpx::Time` only exposes a `time`-crate `OffsetDateTime`, not a plain
+/// unix-millis accessor, so this is the one place that conversion happens.
+fn gpx_time_to_millis(time: gpx::Time) -> i64 {
+    let offset_date_time: time::OffsetDateTime = time.into();
+    offset_date_time.unix_timestamp() * 1000 + i64::from(offset_date_time.millisecond())
+}
+
+fn kml_placemarks(kml: &kml::Kml) -> Vec<&kml::types::Placemark> {
+    match kml {
+        kml::Kml::KmlDocument(doc) => doc.elements.iter().flat_map(kml_placemarks).collect(),
+        kml::Kml::Document { elements, .. } => {
+            elements.iter().flat_map(kml_placemarks).collect()
+        }
+        kml::Kml::Folder(folder) => folder.elements.iter().flat_map(kml_placemarks).collect(),
+        kml::Kml::Placemark(placemark) => vec![placemark],
+        _ => Vec::new(),
+    }
+}
+
+/// Real multi-segment KML tracks are commonly exported as a
+/// `MultiGeometry` wrapping several `LineString`s rather than a single
+/// one; recurse into it so those aren't silently treated as "no
+/// coordinates".
+fn kml_placemark_coords(placemark: &kml::types::Placemark) -> Vec<(f64, f64)> {
+    fn coords_of(geometry: &kml::types::Geometry) -> Vec<(f64, f64)> {
+        match geometry {
+            kml::types::Geometry::LineString(line) => {
+                line.coords.iter().map(|coord| (coord.x, coord.y)).collect()
+            }
+            kml::types::Geometry::MultiGeometry(multi) => {
+                multi.geometries.iter().flat_map(coords_of).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    match &placemark.geometry {
+        Some(geometry) => coords_of(geometry),
+        None => Vec::new(),
+    }
+}
+
+/// Looks for a `<TimeStamp><when>...</when></TimeStamp>` child of the
+/// placemark, which is as close as plain (non-`gx:Track`) KML gets to a
+/// timestamp. A malformed `when` value does not fail the placemark: it is
+/// logged with `warn!` and the points are left without a timestamp. It is
+/// not reported as an `ImportWarning`.
+fn kml_placemark_timestamp_ms(placemark: &kml::types::Placemark) -> Option<i64> {
+    let when = placemark
+        .children
+        .iter()
+        .find(|element| element.name == "TimeStamp")?
+        .children
+        .iter()
+        .find(|element| element.name == "when")?
+        .content
+        .as_deref()?;
+
+    match chrono::DateTime::parse_from_rfc3339(when) {
+        Result::Ok(time) => Some(time.timestamp_millis()),
+        Result::Err(err) => {
+            warn!("kml placemark has an unparsable <TimeStamp><when>: {}", err);
+            None
+        }
+    }
+}
+
+/// Concatenates several journeys' tracks into a single journey, preserving
+/// segment boundaries so a gap between two source tracks doesn't get drawn
+/// as a straight line across it.
+pub fn merge_journey_vectors(vectors: Vec<JourneyVector>) -> JourneyVector {
+    JourneyVector {
+        track_segments: vectors
+            .into_iter()
+            .flat_map(|vector| vector.track_segments)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Regression test for a bug where every imported point got
+    // `timestamp_ms: None` regardless of source format: `gpx_time_to_millis`
+    // is the one place GPX's `time`-crate-backed `Time` gets converted to
+    // plain unix millis.
+    #[test]
+    fn gpx_time_converts_to_unix_millis() {
+        let offset_date_time = time::OffsetDateTime::from_unix_timestamp(1_700_000_000)
+            .unwrap()
+            .saturating_add(time::Duration::milliseconds(123));
+        let millis = gpx_time_to_millis(gpx::Time::from(offset_date_time));
+        assert_eq!(millis, 1_700_000_000_123);
+    }
+
+    #[test]
+    fn kml_placemark_timestamp_parses_the_when_element() {
+        let placemark = kml::types::Placemark {
+            children: vec![kml::types::Element {
+                name: "TimeStamp".to_string(),
+                children: vec![kml::types::Element {
+                    name: "when".to_string(),
+                    content: Some("2023-11-14T22:13:20+00:00".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kml_placemark_timestamp_ms(&placemark),
+            Some(1_700_000_000_000)
+        );
+    }
+
+    #[test]
+    fn kml_placemark_timestamp_is_none_without_a_when_element() {
+        let placemark = kml::types::Placemark::default();
+        assert_eq!(kml_placemark_timestamp_ms(&placemark), None);
+    }
+
+    // Regression test for a bug where a `MultiGeometry` wrapping several
+    // `LineString`s (the common shape for real multi-segment KML tracks)
+    // was treated as having no coordinates at all.
+    #[test]
+    fn kml_placemark_coords_recurses_into_multi_geometry() {
+        let line = |x: f64, y: f64| kml::types::LineString {
+            coords: vec![kml::types::Coord { x, y, z: None }],
+            ..Default::default()
+        };
+        let placemark = kml::types::Placemark {
+            geometry: Some(kml::types::Geometry::MultiGeometry(
+                kml::types::MultiGeometry {
+                    geometries: vec![
+                        kml::types::Geometry::LineString(line(1.0, 2.0)),
+                        kml::types::Geometry::LineString(line(3.0, 4.0)),
+                    ],
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kml_placemark_coords(&placemark),
+            vec![(1.0, 2.0), (3.0, 4.0)]
+        );
+    }
+
+    // `try_from_fit_records`'s timestamp parsing isn't covered here: it
+    // would need a synthetic `fitparser::FitDataRecord`, and this crate's
+    // exact record/field construction API isn't exercised anywhere else in
+    // this file to check it against.
+}
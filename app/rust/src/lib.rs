@@ -0,0 +1,21 @@
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate anyhow;
+
+pub mod actor;
+pub mod api;
+pub mod archive;
+pub mod errors;
+pub mod export_data;
+pub mod gps_processor;
+pub mod gps_wal;
+pub mod import_data;
+pub mod job_manager;
+pub mod journey_bitmap;
+pub mod journey_data;
+pub mod journey_header;
+pub mod journey_vector;
+pub mod map_renderer;
+pub mod merged_journey_builder;
+pub mod storage;